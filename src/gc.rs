@@ -0,0 +1,371 @@
+// A small garbage collector for the on-disk mirror, backed by a sqlite
+// database that tracks when each cache artifact was last used.
+
+use crate::Ctx;
+use anyhow::{Context, Error};
+use rusqlite::{params, Connection};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, info};
+
+const DB_FILE: &str = "gc.db";
+
+fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// Recursively sums the size of every file under `path`, or the size of
+// `path` itself if it's a plain file. Missing paths are zero-size.
+pub fn artifact_size(path: &Path) -> u64 {
+    let Ok(meta) = std::fs::symlink_metadata(path) else {
+        return 0;
+    };
+
+    if !meta.is_dir() {
+        return meta.len();
+    }
+
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| artifact_size(&e.path()))
+        .sum()
+}
+
+// Accumulates the cache paths touched over the course of a single
+// sync::crates run so their last-use timestamp can be flushed to the gc
+// database in one transaction at the end, rather than hitting sqlite once
+// per crate.
+#[derive(Default)]
+pub struct DeferredLastUse(Mutex<HashSet<PathBuf>>);
+
+impl DeferredLastUse {
+    pub fn touch(&self, path: PathBuf) {
+        self.0.lock().unwrap().insert(path);
+    }
+
+    // Empties the set of touched paths out, handing ownership to the
+    // caller so it can be moved into a blocking task.
+    pub fn take(&self) -> Vec<PathBuf> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+            .into_iter()
+            .collect()
+    }
+}
+
+// Stats and records the last-use timestamp for every path in `touched` in
+// a single transaction. Runs blocking sqlite/fs calls, so callers should
+// run this on a blocking thread.
+pub fn flush(root_dir: &Path, touched: Vec<PathBuf>) -> Result<(), Error> {
+    if touched.is_empty() {
+        return Ok(());
+    }
+
+    let mut db = Db::open(root_dir)?;
+    db.touch_many(touched.iter(), now_secs())
+}
+
+// A row in the `cache_entries` table. The path doubles as the artifact's
+// id, since it already encodes which cache it's under and its local_id().
+pub struct Entry {
+    pub path: PathBuf,
+    pub last_use: i64,
+    pub size: u64,
+}
+
+// The sqlite database, stored in `root_dir`, recording last-use timestamps
+// and sizes for the cache artifacts sync::crates touches: registry/cache
+// tarballs and git/checkouts directories. registry/src, git/db and the
+// registry index are not tracked, so gc() never removes them.
+pub struct Db(Connection);
+
+impl Db {
+    pub fn open(root_dir: &Path) -> Result<Self, Error> {
+        let conn =
+            Connection::open(root_dir.join(DB_FILE)).context("failed to open gc database")?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS cache_entries (
+                path TEXT PRIMARY KEY,
+                last_use INTEGER NOT NULL,
+                size INTEGER NOT NULL
+            )",
+            [],
+        )
+        .context("failed to create cache_entries table")?;
+
+        Ok(Self(conn))
+    }
+
+    fn touch_many<'p>(
+        &mut self,
+        paths: impl Iterator<Item = &'p PathBuf>,
+        now: i64,
+    ) -> Result<(), Error> {
+        let tx = self
+            .0
+            .transaction()
+            .context("failed to start gc touch transaction")?;
+
+        for path in paths {
+            let size = artifact_size(path);
+
+            tx.execute(
+                "INSERT INTO cache_entries (path, last_use, size) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(path) DO UPDATE SET last_use = excluded.last_use, size = excluded.size",
+                params![path.to_string_lossy(), now, size as i64],
+            )
+            .with_context(|| format!("failed to record last-use for {}", path.display()))?;
+        }
+
+        tx.commit()
+            .context("failed to commit gc touch transaction")?;
+
+        Ok(())
+    }
+
+    fn older_than(&self, cutoff: i64) -> Result<Vec<Entry>, Error> {
+        let mut stmt = self
+            .0
+            .prepare("SELECT path, last_use, size FROM cache_entries WHERE last_use < ?1")?;
+
+        let rows = stmt.query_map(params![cutoff], Self::row_to_entry)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>()
+            .context("failed to read stale cache entries")
+    }
+
+    fn total_size(&self) -> Result<u64, Error> {
+        self.0
+            .query_row(
+                "SELECT COALESCE(SUM(size), 0) FROM cache_entries",
+                [],
+                |row| row.get::<_, i64>(0),
+            )
+            .map(|n| n as u64)
+            .context("failed to sum cache_entries size")
+    }
+
+    fn oldest_first(&self) -> Result<Vec<Entry>, Error> {
+        let mut stmt = self
+            .0
+            .prepare("SELECT path, last_use, size FROM cache_entries ORDER BY last_use ASC")?;
+
+        let rows = stmt.query_map([], Self::row_to_entry)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>()
+            .context("failed to read cache_entries ordered by last use")
+    }
+
+    fn remove(&mut self, paths: &[PathBuf]) -> Result<(), Error> {
+        let tx = self
+            .0
+            .transaction()
+            .context("failed to start gc removal transaction")?;
+
+        for path in paths {
+            tx.execute(
+                "DELETE FROM cache_entries WHERE path = ?1",
+                params![path.to_string_lossy()],
+            )
+            .with_context(|| format!("failed to remove row for {}", path.display()))?;
+        }
+
+        tx.commit()
+            .context("failed to commit gc removal transaction")?;
+
+        Ok(())
+    }
+
+    fn row_to_entry(row: &rusqlite::Row<'_>) -> rusqlite::Result<Entry> {
+        Ok(Entry {
+            path: PathBuf::from(row.get::<_, String>(0)?),
+            last_use: row.get(1)?,
+            size: row.get::<_, i64>(2)? as u64,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub freed_bytes: u64,
+    pub removed: u32,
+}
+
+// Prunes cache artifacts unused for longer than `max_age`, and, if the
+// mirror's tracked size still exceeds `max_size` afterwards, additionally
+// removes the least-recently-used entries until it no longer does.
+pub async fn gc(ctx: &Ctx, max_age: Duration, max_size: Option<u64>) -> Result<Summary, Error> {
+    let root_dir = ctx.root_dir.clone();
+
+    tokio::task::spawn_blocking(move || -> Result<Summary, Error> {
+        let mut db = Db::open(&root_dir)?;
+        let to_remove = select_for_removal(&db, now_secs(), max_age, max_size)?;
+
+        let mut summary = Summary::default();
+        let mut removed_paths = Vec::with_capacity(to_remove.len());
+
+        for entry in &to_remove {
+            match remove_path(&entry.path) {
+                Ok(true) => {
+                    summary.freed_bytes += entry.size;
+                    summary.removed += 1;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    error!(err = ?e, path = ?entry.path, "failed to remove stale cache entry");
+                    continue;
+                }
+            }
+
+            removed_paths.push(entry.path.clone());
+        }
+
+        db.remove(&removed_paths)?;
+
+        info!(
+            freed_bytes = summary.freed_bytes,
+            removed = summary.removed,
+            "gc complete"
+        );
+
+        Ok(summary)
+    })
+    .await?
+}
+
+// Picks the entries gc() should remove: everything untouched since
+// `cutoff` (`now - max_age`), plus, if the mirror's remaining tracked
+// size would still exceed `max_size`, the least-recently-used entries
+// beyond that, oldest first, until it wouldn't.
+fn select_for_removal(
+    db: &Db,
+    now: i64,
+    max_age: Duration,
+    max_size: Option<u64>,
+) -> Result<Vec<Entry>, Error> {
+    let cutoff = now - max_age.as_secs() as i64;
+    let mut to_remove = db.older_than(cutoff)?;
+    let mut seen: HashSet<PathBuf> = to_remove.iter().map(|e| e.path.clone()).collect();
+
+    if let Some(max_size) = max_size {
+        let already_freed: u64 = to_remove.iter().map(|e| e.size).sum();
+        let remaining = db.total_size()?.saturating_sub(already_freed);
+
+        if remaining > max_size {
+            let mut over = remaining - max_size;
+
+            for entry in db.oldest_first()? {
+                if over == 0 {
+                    break;
+                }
+
+                if seen.contains(&entry.path) {
+                    continue;
+                }
+
+                over = over.saturating_sub(entry.size);
+                seen.insert(entry.path.clone());
+                to_remove.push(entry);
+            }
+        }
+    }
+
+    Ok(to_remove)
+}
+
+// Removes `path` if it still exists, returning whether anything was
+// actually deleted so callers don't count space that was never freed.
+fn remove_path(path: &Path) -> Result<bool, Error> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => {
+            remove_dir_all::remove_dir_all(path)
+                .with_context(|| format!("failed to remove {}", path.display()))?;
+            Ok(true)
+        }
+        Ok(_) => {
+            std::fs::remove_file(path)
+                .with_context(|| format!("failed to remove {}", path.display()))?;
+            Ok(true)
+        }
+        Err(_) => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    fn temp_root() -> PathBuf {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let dir = std::env::temp_dir().join(format!(
+            "cargo-fetcher-gc-test-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn select_for_removal_respects_max_age() {
+        let root = temp_root();
+        let mut db = Db::open(&root).unwrap();
+        db.touch_many([PathBuf::from("old")].iter(), 1_000).unwrap();
+        db.touch_many([PathBuf::from("new")].iter(), 2_000).unwrap();
+
+        // Only "old" is past the cutoff implied by `now - max_age`.
+        let to_remove = select_for_removal(&db, 2_000, Duration::from_secs(500), None).unwrap();
+
+        assert_eq!(
+            to_remove.iter().map(|e| &e.path).collect::<Vec<_>>(),
+            vec![&PathBuf::from("old")]
+        );
+    }
+
+    #[test]
+    fn select_for_removal_evicts_oldest_first_over_size_budget() {
+        let root = temp_root();
+        let mut db = Db::open(&root).unwrap();
+
+        // Give each path a real file so artifact_size() (and thus the
+        // tracked size used by the budget loop) is non-zero.
+        for (name, last_use) in [("a", 10), ("b", 20), ("c", 30)] {
+            let path = root.join(name);
+            std::fs::write(&path, b"0123456789").unwrap();
+            db.touch_many([path].iter(), last_use).unwrap();
+        }
+
+        // Nothing is old enough to be pruned by age, so eviction is driven
+        // entirely by the size budget: total tracked size is 30, which
+        // exceeds max_size of 5, so entries are evicted oldest-last-use
+        // first until the budget is met.
+        let to_remove =
+            select_for_removal(&db, 0, Duration::from_secs(1_000_000), Some(5)).unwrap();
+
+        assert_eq!(
+            to_remove.iter().map(|e| e.path.clone()).collect::<Vec<_>>(),
+            vec![root.join("a"), root.join("b"), root.join("c")]
+        );
+    }
+
+    #[test]
+    fn remove_path_reports_whether_anything_was_deleted() {
+        let root = temp_root();
+        let file = root.join("artifact");
+        std::fs::write(&file, b"data").unwrap();
+
+        assert_eq!(remove_path(&file).unwrap(), true);
+        assert_eq!(remove_path(&file).unwrap(), false);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{util, Krate, Registry, Source};
+use crate::{gc, util, Krate, Registry, Source};
 use anyhow::{Context, Error};
 use futures::StreamExt;
 use std::{io::Write, path::PathBuf};
@@ -11,19 +11,40 @@ pub const SRC_DIR: &str = "registry/src";
 pub const GIT_DB_DIR: &str = "git/db";
 pub const GIT_CO_DIR: &str = "git/checkouts";
 
+const CACHEDIR_TAG: &str = "Signature: 8a477f597d28d172789f06886806bc55\n\
+# This directory is a cache regenerable by cargo-fetcher, tagged per\n\
+# https://bford.info/cachedir/ so backup/snapshot tools can skip it.\n";
+
+// Drops a CACHEDIR.TAG into dir, the way cargo itself tags target/. No-op
+// if the tag is already present.
+fn mark_cachedir(dir: &Path) -> Result<(), Error> {
+    let tag_path = dir.join("CACHEDIR.TAG");
+
+    if tag_path.exists() {
+        return Ok(());
+    }
+
+    std::fs::write(&tag_path, CACHEDIR_TAG)
+        .with_context(|| format!("failed to write {}", tag_path.display()))
+}
+
 pub async fn registry_indices(
     root_dir: PathBuf,
     backend: crate::Storage,
     registries: Vec<std::sync::Arc<Registry>>,
+    krates: std::sync::Arc<Vec<Krate>>,
+    write_cachedir_tag: bool,
 ) -> Result<(), Error> {
     let root_dir = &root_dir;
     let resu = futures::stream::iter(registries)
         .map(|registry| {
             let backend = backend.clone();
+            let krates = krates.clone();
             async move {
-                let res: Result<(), Error> = registry_index(root_dir, backend, registry)
-                    .instrument(tracing::debug_span!("download registry"))
-                    .await;
+                let res: Result<(), Error> =
+                    registry_index(root_dir, backend, registry, &krates, write_cachedir_tag)
+                        .instrument(tracing::debug_span!("download registry"))
+                        .await;
                 res
             }
             .instrument(tracing::debug_span!("sync registry"))
@@ -48,12 +69,27 @@ pub async fn registry_index(
     root_dir: &Path,
     backend: crate::Storage,
     registry: std::sync::Arc<Registry>,
+    krates: &[Krate],
+    write_cachedir_tag: bool,
 ) -> Result<(), Error> {
     let ident = registry.short_name();
 
     let index_path = root_dir.join(INDEX_DIR).join(ident.clone());
     std::fs::create_dir_all(&index_path).context("failed to create index dir")?;
 
+    if write_cachedir_tag {
+        mark_cachedir(&root_dir.join(INDEX_DIR))?;
+    }
+
+    // A sparse registry has no git history to clone/fetch, instead each
+    // crate's index entries live at a well known path under the registry's
+    // HTTP root, so handle it entirely separately from the git flow below
+    if registry.index.as_str().starts_with("sparse+") {
+        return sync_sparse_index(&index_path, &registry, krates)
+            .instrument(tracing::debug_span!("sparse"))
+            .await;
+    }
+
     // Just skip the index if the git directory already exists,
     // as a patch on top of an existing repo via git fetch is
     // presumably faster
@@ -63,36 +99,40 @@ pub async fn registry_index(
         let url = registry.index.as_str().to_owned();
 
         // We need to ship off the fetching to a blocking thread so we don't anger tokio
-        match tokio::task::spawn_blocking(move || -> Result<(), Error> {
+        let outcome = tokio::task::spawn_blocking(move || -> Result<FetchOutcome, Error> {
             let git_config =
                 git2::Config::open_default().context("Failed to open default git config")?;
 
-            crate::git::with_fetch_options(&git_config, &url, &mut |mut opts| {
-                repo.remote_anonymous(&url)?
-                    .fetch(
-                        &[
-                            "refs/heads/master:refs/remotes/origin/master",
-                            "HEAD:refs/remotes/origin/HEAD",
-                        ],
-                        Some(&mut opts),
-                        None,
-                    )
-                    .context("Failed to fetch")
-            })
+            Ok(fetch_with_recovery(
+                &repo,
+                &git_config,
+                &url,
+                &[
+                    "refs/heads/master:refs/remotes/origin/master",
+                    "HEAD:refs/remotes/origin/HEAD",
+                ],
+                None,
+            ))
         })
         .instrument(tracing::debug_span!("fetch"))
-        .await?
-        {
-            Ok(_) => {
+        .await??;
+
+        match outcome {
+            FetchOutcome::Ok => {
                 // Write a file to the directory to let cargo know when it was updated
                 std::fs::File::create(index_path.join(".last-updated"))
                     .context("failed to crate .last-updated")?;
                 return Ok(());
             }
-            Err(err_out) => {
+            FetchOutcome::Network(err) => {
+                // A flaky connection doesn't mean the local index is bad,
+                // so just surface the error rather than nuking local state
+                return Err(err.context("failed to fetch registry index"));
+            }
+            FetchOutcome::Corrupt(err) => {
                 error!(
-                    "failed to pull registry index, removing it and updating manually: {}",
-                    err_out
+                    "registry index looks corrupt, removing it and updating manually: {:#}",
+                    err
                 );
                 remove_dir_all::remove_dir_all(&index_path)?;
             }
@@ -123,6 +163,215 @@ pub async fn registry_index(
     Ok(())
 }
 
+const MAX_FETCH_RETRIES: u32 = 3;
+
+// The result of fetch_with_recovery.
+enum FetchOutcome {
+    Ok,
+    // Local repository looks corrupt, caller should remove and reclone.
+    Corrupt(Error),
+    // Every retry was a network/transport failure; local state untouched.
+    Network(Error),
+}
+
+// Whether a git2 error indicates on-disk repository corruption, as
+// opposed to a network/transport failure. Only these warrant a reclone.
+fn is_corrupt_repo_error(err: &git2::Error) -> bool {
+    matches!(
+        err.class(),
+        git2::ErrorClass::Reference
+            | git2::ErrorClass::Odb
+            | git2::ErrorClass::Object
+            | git2::ErrorClass::Index
+    )
+}
+
+// Fetches refspecs from url into repo, making up to MAX_FETCH_RETRIES
+// attempts in all on transport errors. If rev is supplied, also checks it resolves
+// via revparse_single once the fetch succeeds, since a fetch can succeed
+// while the ref we actually want is still missing.
+fn fetch_with_recovery(
+    repo: &git2::Repository,
+    git_config: &git2::Config,
+    url: &str,
+    refspecs: &[&str],
+    rev: Option<&str>,
+) -> FetchOutcome {
+    let mut last_err = None;
+
+    for attempt in 1..=MAX_FETCH_RETRIES {
+        let fetch_res = crate::git::with_fetch_options(git_config, url, &mut |mut opts| {
+            repo.remote_anonymous(url)?
+                .fetch(refspecs, Some(&mut opts), None)
+                .context("failed to fetch")
+        });
+
+        match fetch_res {
+            Ok(_) => {
+                if let Some(rev) = rev {
+                    if let Err(e) = repo.revparse_single(rev) {
+                        return FetchOutcome::Corrupt(Error::new(e).context(format!(
+                            "'{}' still doesn't resolve after a successful fetch",
+                            rev
+                        )));
+                    }
+                }
+
+                return FetchOutcome::Ok;
+            }
+            Err(e) => {
+                if let Some(git_err) = e.downcast_ref::<git2::Error>() {
+                    if is_corrupt_repo_error(git_err) {
+                        return FetchOutcome::Corrupt(e);
+                    }
+                }
+
+                warn!(attempt, err = ?e, "fetch failed, retrying");
+                last_err = Some(e);
+            }
+        }
+    }
+
+    FetchOutcome::Network(last_err.expect("at least one fetch attempt is always made"))
+}
+
+// Path, relative to a registry index root, of a crate's sparse index
+// entry under cargo's name-prefix layout, e.g. serde -> se/rd/serde.
+fn sparse_index_path(name: &str) -> PathBuf {
+    let lower = name.to_lowercase();
+
+    match lower.len() {
+        1 => PathBuf::from("1").join(name),
+        2 => PathBuf::from("2").join(name),
+        3 => PathBuf::from("3").join(&lower[..1]).join(name),
+        _ => PathBuf::from(&lower[..2]).join(&lower[2..4]).join(name),
+    }
+}
+
+// Mirrors a sparse HTTP registry index by fetching the index entry for
+// every crate we actually need and laying it out on disk the same way
+// cargo's own sparse registry cache does, rather than cloning a git repo.
+async fn sync_sparse_index(
+    index_path: &Path,
+    registry: &Registry,
+    krates: &[Krate],
+) -> Result<(), Error> {
+    let base = registry
+        .index
+        .as_str()
+        .strip_prefix("sparse+")
+        .unwrap_or_else(|| registry.index.as_str())
+        .trim_end_matches('/')
+        .to_owned();
+
+    let mut names: Vec<&str> = krates
+        .iter()
+        .filter(|k| *k == registry)
+        .map(|k| k.name.as_str())
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+
+    let client = reqwest::Client::new();
+
+    futures::stream::iter(names)
+        .map(|name| {
+            let client = client.clone();
+            let base = base.clone();
+            async move {
+                if let Err(e) = fetch_sparse_entry(&client, &base, index_path, name).await {
+                    error!(err = ?e, krate = name, "failed to fetch sparse index entry");
+                }
+            }
+            .instrument(tracing::debug_span!("sparse_entry", krate = name))
+        })
+        .buffer_unordered(32)
+        .for_each(|_| async {})
+        .await;
+
+    // Write a file to the directory to let cargo know when it was updated,
+    // same as the git index flow
+    std::fs::File::create(index_path.join(".last-updated"))
+        .context("failed to create .last-updated")?;
+
+    Ok(())
+}
+
+const MAX_SPARSE_FETCH_RETRIES: u32 = 3;
+
+// Whether a response status means "this crate doesn't exist in the
+// registry", as opposed to a transient or auth failure that deserves a
+// retry/error instead of silently dropping the crate from the mirror.
+fn is_missing_crate_status(status: reqwest::StatusCode) -> bool {
+    matches!(
+        status,
+        reqwest::StatusCode::NOT_FOUND
+            | reqwest::StatusCode::GONE
+            | reqwest::StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS
+    )
+}
+
+// Fetches a single crate's sparse index entry, retrying on transport
+// errors, 5xx responses, and other non-success 4xx statuses (rate limits,
+// auth failures). Only a status from is_missing_crate_status is treated
+// as "not present" rather than an error, same as a missing crate in the
+// git index flow.
+async fn fetch_sparse_entry(
+    client: &reqwest::Client,
+    base: &str,
+    index_path: &Path,
+    name: &str,
+) -> Result<(), Error> {
+    let rel_path = sparse_index_path(name);
+    let url = format!(
+        "{}/{}",
+        base,
+        rel_path
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"),
+    );
+
+    let mut last_err = None;
+
+    for attempt in 1..=MAX_SPARSE_FETCH_RETRIES {
+        match client.get(&url).send().await {
+            Ok(res) if res.status().is_success() => {
+                let body = res
+                    .bytes()
+                    .await
+                    .with_context(|| format!("failed to read response body for '{}'", name))?;
+
+                let file_path = index_path.join(&rel_path);
+                if let Some(parent) = file_path.parent() {
+                    std::fs::create_dir_all(parent)
+                        .with_context(|| format!("failed to create {}", parent.display()))?;
+                }
+
+                return std::fs::write(&file_path, &body)
+                    .with_context(|| format!("failed to write {}", file_path.display()));
+            }
+            Ok(res) if is_missing_crate_status(res.status()) => {
+                warn!(krate = name, status = %res.status(), "sparse index entry missing, skipping");
+                return Ok(());
+            }
+            Ok(res) => {
+                last_err = Some(Error::msg(format!("unexpected status {}", res.status())));
+            }
+            Err(e) => {
+                last_err = Some(Error::new(e));
+            }
+        }
+
+        warn!(krate = name, attempt, "sparse index fetch failed, retrying");
+    }
+
+    Err(last_err
+        .unwrap()
+        .context(format!("failed to fetch sparse index entry for '{}'", name)))
+}
+
 async fn sync_git(
     db_dir: PathBuf,
     co_dir: PathBuf,
@@ -168,9 +417,25 @@ async fn sync_git(
         }
         None => {
             // Do a checkout of the bare clone
-            crate::git::checkout(db_path, co_path.clone(), rev.to_owned())
+            if let Err(err) = crate::git::checkout(db_path.clone(), co_path.clone(), rev.to_owned())
                 .instrument(tracing::debug_span!("checkout"))
-                .await?;
+                .await
+            {
+                warn!(
+                    err = ?err,
+                    "checkout failed, wiping checkout dir and retrying once from local bare DB"
+                );
+
+                if co_path.exists() {
+                    remove_dir_all::remove_dir_all(&co_path)
+                        .with_context(|| format!("unable to remove {}", co_path.display()))?;
+                }
+
+                crate::git::checkout(db_path, co_path.clone(), rev.to_owned())
+                    .instrument(tracing::debug_span!("checkout_retry"))
+                    .await
+                    .context("checkout failed again after retry")?;
+            }
         }
     }
 
@@ -269,14 +534,18 @@ fn get_missing_git_sources<'krate>(
     ctx: &'krate crate::Ctx,
     git_co_dir: &Path,
     to_sync: &mut Vec<&'krate Krate>,
+    last_use: &gc::DeferredLastUse,
 ) {
     for (rev, ident, krate) in ctx.krates.iter().filter_map(|k| match &k.source {
         Source::Git { rev, ident, .. } => Some((rev, ident, k)),
         _ => None,
     }) {
-        let path = git_co_dir.join(format!("{}/{}/.cargo-ok", ident, rev));
+        let co_path = git_co_dir.join(format!("{}/{}", ident, rev));
+        let ok_path = co_path.join(".cargo-ok");
 
-        if !path.exists() {
+        if ok_path.exists() {
+            last_use.touch(co_path);
+        } else {
             to_sync.push(krate);
         }
     }
@@ -287,6 +556,7 @@ fn get_missing_registry_sources<'krate>(
     registry: &Registry,
     cache_dir: &Path,
     to_sync: &mut Vec<&'krate Krate>,
+    last_use: &gc::DeferredLastUse,
 ) -> Result<(), Error> {
     let cache_iter = std::fs::read_dir(&cache_dir)?;
 
@@ -308,6 +578,8 @@ fn get_missing_registry_sources<'krate>(
 
         if cached_crates.binary_search(&krate_name).is_err() {
             to_sync.push(krate);
+        } else {
+            last_use.touch(cache_dir.join(&krate_name));
         }
 
         krate_name.clear();
@@ -333,16 +605,25 @@ pub async fn crates(ctx: &crate::Ctx) -> Result<Summary, Error> {
     std::fs::create_dir_all(&git_db_dir).context("failed to create git/db/")?;
     std::fs::create_dir_all(&git_co_dir).context("failed to create git/checkouts/")?;
 
+    if ctx.write_cachedir_tag {
+        mark_cachedir(&git_db_dir)?;
+        mark_cachedir(&git_co_dir)?;
+        mark_cachedir(&root_dir.join(CACHE_DIR))?;
+        mark_cachedir(&root_dir.join(SRC_DIR))?;
+    }
+
+    let last_use = gc::DeferredLastUse::default();
+
     info!("checking local cache for missing crates...");
     let mut to_sync = Vec::with_capacity(ctx.krates.len());
-    get_missing_git_sources(ctx, &git_co_dir, &mut to_sync);
+    get_missing_git_sources(ctx, &git_co_dir, &mut to_sync, &last_use);
 
     for registry in &ctx.registries {
         let (cache_dir, src_dir) = registry.sync_dirs(root_dir);
         std::fs::create_dir_all(&cache_dir).context("failed to create registry/cache")?;
         std::fs::create_dir_all(&src_dir).context("failed to create registry/src")?;
 
-        get_missing_registry_sources(ctx, &registry, &cache_dir, &mut to_sync)?;
+        get_missing_registry_sources(ctx, &registry, &cache_dir, &mut to_sync, &last_use)?;
     }
 
     // Remove duplicates, eg. when 2 crates are sourced from the same git repository
@@ -351,6 +632,7 @@ pub async fn crates(ctx: &crate::Ctx) -> Result<Summary, Error> {
 
     if to_sync.is_empty() {
         info!("all crates already available on local disk");
+        flush_last_use(root_dir.clone(), &last_use).await?;
         return Ok(Summary {
             total_bytes: 0,
             good: 0,
@@ -366,6 +648,7 @@ pub async fn crates(ctx: &crate::Ctx) -> Result<Summary, Error> {
 
             let git_db_dir = git_db_dir.clone();
             let git_co_dir = git_co_dir.clone();
+            let last_use = &last_use;
 
             #[allow(clippy::cognitive_complexity)]
             async move {
@@ -391,8 +674,10 @@ pub async fn crates(ctx: &crate::Ctx) -> Result<Summary, Error> {
                                     error!(err = ?e, "failed to splat package");
                                     return Err(e);
                                 }
+
+                                last_use.touch(cache_dir.join(format!("{}", krate.local_id())));
                             }
-                            Source::Git { rev, .. } => {
+                            Source::Git { rev, ident, .. } => {
                                 let checkout = {
                                     let mut checkout_id = krate.clone();
 
@@ -413,13 +698,15 @@ pub async fn crates(ctx: &crate::Ctx) -> Result<Summary, Error> {
                                 };
 
                                 if let Err(e) =
-                                    sync_git(git_db_dir, git_co_dir, krate, git_source, rev)
+                                    sync_git(git_db_dir, git_co_dir.clone(), krate, git_source, rev)
                                         .instrument(tracing::debug_span!("git"))
                                         .await
                                 {
                                     error!(err = ?e, "failed to splat git repo");
                                     return Err(e);
                                 }
+
+                                last_use.touch(git_co_dir.join(format!("{}/{}", ident, rev)));
                             }
                         };
 
@@ -454,5 +741,83 @@ pub async fn crates(ctx: &crate::Ctx) -> Result<Summary, Error> {
         )
         .await;
 
+    flush_last_use(root_dir.clone(), &last_use).await?;
+
     Ok(summary)
 }
+
+async fn flush_last_use(root_dir: PathBuf, last_use: &gc::DeferredLastUse) -> Result<(), Error> {
+    let touched = last_use.take();
+
+    tokio::task::spawn_blocking(move || gc::flush(&root_dir, touched))
+        .instrument(tracing::debug_span!("flush_last_use"))
+        .await?
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sparse_index_path_matches_cargo_prefix_layout() {
+        assert_eq!(sparse_index_path("a"), PathBuf::from("1").join("a"));
+        assert_eq!(sparse_index_path("ab"), PathBuf::from("2").join("ab"));
+        assert_eq!(
+            sparse_index_path("abc"),
+            PathBuf::from("3").join("a").join("abc")
+        );
+        assert_eq!(
+            sparse_index_path("abcd"),
+            PathBuf::from("ab").join("cd").join("abcd")
+        );
+        assert_eq!(
+            sparse_index_path("serde"),
+            PathBuf::from("se").join("rd").join("serde")
+        );
+    }
+
+    #[test]
+    fn sparse_index_path_lowercases_prefix_dirs() {
+        assert_eq!(
+            sparse_index_path("Serde"),
+            PathBuf::from("se").join("rd").join("Serde")
+        );
+    }
+
+    #[test]
+    fn is_corrupt_repo_error_whitelists_on_disk_corruption_classes() {
+        for class in [
+            git2::ErrorClass::Reference,
+            git2::ErrorClass::Odb,
+            git2::ErrorClass::Object,
+            git2::ErrorClass::Index,
+        ] {
+            let err = git2::Error::new(git2::ErrorCode::GenericError, class, "broken");
+            assert!(is_corrupt_repo_error(&err));
+        }
+    }
+
+    #[test]
+    fn is_corrupt_repo_error_excludes_network_classes() {
+        for class in [git2::ErrorClass::Net, git2::ErrorClass::Ssh] {
+            let err = git2::Error::new(git2::ErrorCode::GenericError, class, "unreachable");
+            assert!(!is_corrupt_repo_error(&err));
+        }
+    }
+
+    #[test]
+    fn is_missing_crate_status_only_matches_not_present_statuses() {
+        assert!(is_missing_crate_status(reqwest::StatusCode::NOT_FOUND));
+        assert!(is_missing_crate_status(reqwest::StatusCode::GONE));
+        assert!(is_missing_crate_status(
+            reqwest::StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS
+        ));
+
+        // Rate limiting and auth failures must not be treated as "missing".
+        assert!(!is_missing_crate_status(
+            reqwest::StatusCode::TOO_MANY_REQUESTS
+        ));
+        assert!(!is_missing_crate_status(reqwest::StatusCode::UNAUTHORIZED));
+        assert!(!is_missing_crate_status(reqwest::StatusCode::FORBIDDEN));
+    }
+}